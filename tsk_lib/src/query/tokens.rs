@@ -14,9 +14,59 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use chrono::prelude::*;
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
 use std::fmt;
 
-#[derive(Debug, PartialEq, Clone)]
+// `Token::Date` round-trips through serde as an RFC 3339 string so serialized
+// token streams are human-readable and timezone-unambiguous.
+mod date_serde {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(dte: &DateTime<Local>, ser: S) -> Result<S::Ok, S::Error> {
+        ser.serialize_str(&dte.to_rfc3339())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(de: D) -> Result<DateTime<Local>, D::Error> {
+        let raw = String::deserialize(de)?;
+        DateTime::parse_from_rfc3339(&raw)
+            .map(|dte| dte.with_timezone(&Local))
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+// `Token::Float` serializes as a plain number when finite and falls back to a
+// string for the non-finite values (`NaN`/`inf`/`-inf`) that most data formats
+// cannot represent natively.
+mod float_serde {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Num(f64),
+        Str(String),
+    }
+
+    pub fn serialize<S: Serializer>(num: &f64, ser: S) -> Result<S::Ok, S::Error> {
+        if num.is_finite() {
+            ser.serialize_f64(*num)
+        } else {
+            ser.serialize_str(&num.to_string())
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(de: D) -> Result<f64, D::Error> {
+        match Repr::deserialize(de)? {
+            Repr::Num(num) => Ok(num),
+            Repr::Str(raw) => raw.parse::<f64>().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum Token {
     GT,
     LT,
@@ -29,6 +79,7 @@ pub enum Token {
 
     AND,
     OR,
+    NOT,
 
     LP,
     RP,
@@ -36,8 +87,8 @@ pub enum Token {
     EOF,
 
     Str(String),
-    Float(f64),
-    Date(DateTime<Local>),
+    Float(#[serde(with = "float_serde")] f64),
+    Date(#[serde(with = "date_serde")] DateTime<Local>),
 
     Unexpected(String),
 }
@@ -52,6 +103,8 @@ impl From<char> for Token {
             '=' => Token::EQ,
             '^' => Token::LIKE,
             '~' => Token::LIKE,
+            // A lone `!` negates; the `!~`/`!=` digraphs are lexed as strings.
+            '!' => Token::NOT,
             _ => Token::Unexpected(c.to_string()),
         }
     }
@@ -65,14 +118,184 @@ impl From<char> for Token {
 // %M == 00 - 60 minutes
 const DATE_FORMATS: [&'static str; 3] = ["%F %I:%M %P", "%F %I:%M %p", "%F %H:%M"];
 
-impl<'a> From<&'a str> for Token {
-    fn from(s: &str) -> Token {
+// Map an English weekday name to its chrono counterpart. Only the full,
+// lowercased names are accepted; callers lowercase before reaching here.
+fn weekday_from_name(name: &str) -> Option<Weekday> {
+    match name {
+        "monday" => Some(Weekday::Mon),
+        "tuesday" => Some(Weekday::Tue),
+        "wednesday" => Some(Weekday::Wed),
+        "thursday" => Some(Weekday::Thu),
+        "friday" => Some(Weekday::Fri),
+        "saturday" => Some(Weekday::Sat),
+        "sunday" => Some(Weekday::Sun),
+        _ => None,
+    }
+}
+
+// Last day-of-month for the given year/month, used to clamp naive month and
+// year arithmetic (e.g. Jan 31 + 1 month lands on Feb 28, not Mar 3).
+fn last_day_of_month(year: i32, month: u32) -> u32 {
+    let (next_year, next_month) = if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    };
+
+    NaiveDate::from_ymd(next_year, next_month, 1).pred().day()
+}
+
+// Add (or, for negative counts, subtract) whole months to a local datetime,
+// clamping the day-of-month when the target month is shorter. The time of day
+// is preserved. Returns `None` when the resulting local time doesn't exist
+// (e.g. it lands in a DST spring-forward gap) rather than panicking.
+fn add_months(dte: DateTime<Local>, months: i64) -> Option<DateTime<Local>> {
+    let total = (dte.year() as i64) * 12 + dte.month0() as i64 + months;
+    let year = total.div_euclid(12) as i32;
+    let month = total.rem_euclid(12) as u32 + 1;
+    let day = dte.day().min(last_day_of_month(year, month));
+
+    Local
+        .ymd_opt(year, month, day)
+        .single()?
+        .and_hms_opt(dte.hour(), dte.minute(), dte.second())
+}
+
+// Resolve a relative or fuzzy date expression against `Local::now()`,
+// returning `None` when the input doesn't look like one so the caller can fall
+// through to the strict format loop. Recognizes the bare keywords
+// `today`/`tomorrow`/`yesterday`/`now`, weekday names optionally prefixed with
+// `next`/`last`, the `<N> <unit> (ago|from now|hence)` shape, and a date-only
+// `%F` defaulting the time to midnight.
+fn fuzzy_date(s: &str) -> Option<DateTime<Local>> {
+    let s = s.trim().to_lowercase();
+    let today = Local::today();
+
+    match s.as_str() {
+        "today" => return today.and_hms_opt(0, 0, 0),
+        "tomorrow" => return (today + Duration::days(1)).and_hms_opt(0, 0, 0),
+        "yesterday" => return (today - Duration::days(1)).and_hms_opt(0, 0, 0),
+        "now" => return Some(Local::now()),
+        _ => {}
+    }
+
+    // Weekday names, walking day-by-day from today until the weekday matches.
+    let (step, name) = if s.starts_with("next ") {
+        (1, &s["next ".len()..])
+    } else if s.starts_with("last ") {
+        (-1, &s["last ".len()..])
+    } else {
+        (1, s.as_str())
+    };
+
+    if let Some(target) = weekday_from_name(name) {
+        let mut cursor = today;
+        for _ in 0..7 {
+            cursor = cursor + Duration::days(step);
+            if cursor.weekday() == target {
+                return cursor.and_hms_opt(0, 0, 0);
+            }
+        }
+    }
+
+    // "<N> <unit> (ago|from now|hence)" offsets from the current instant.
+    let words: Vec<&str> = s.split_whitespace().collect();
+    if words.len() >= 3 {
+        if let Ok(count) = words[0].parse::<i64>() {
+            let sign = match words[2..].join(" ").as_str() {
+                "ago" => -1,
+                "from now" | "hence" => 1,
+                _ => 0,
+            };
+
+            if sign != 0 {
+                let count = count * sign;
+                let now = Local::now();
+                match words[1] {
+                    "day" | "days" => return Some(now + Duration::days(count)),
+                    "week" | "weeks" => return Some(now + Duration::weeks(count)),
+                    "month" | "months" => return add_months(now, count),
+                    "year" | "years" => return add_months(now, count * 12),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // A bare `%F` date with no time component defaults to midnight.
+    if let Ok(date) = Local.datetime_from_str(&format!("{} 00:00", s), "%F %H:%M") {
+        return Some(date);
+    }
+
+    None
+}
+
+/// A configurable tokenizer. It owns the set of chrono format strings tried
+/// when lexing dates plus the timezone naive datetimes are interpreted in, so
+/// embedders can tune date policy per deployment instead of relying on the
+/// baked-in [`DATE_FORMATS`].
+///
+/// ```ignore
+/// let lexer = Lexer::new().with_date_format("%d/%m/%Y %H:%M");
+/// lexer.tokenize("04/07/2018 12:00");
+/// ```
+#[derive(Debug, Clone)]
+pub struct Lexer {
+    date_formats: Vec<String>,
+    default_tz: Option<FixedOffset>,
+}
+
+impl Lexer {
+    /// A lexer seeded with the built-in [`DATE_FORMATS`], interpreting naive
+    /// datetimes in the local timezone. Identical to [`Lexer::default`]; use
+    /// [`Lexer::with_date_format`] to add further formats and
+    /// [`Lexer::with_timezone`] to interpret naive datetimes in a fixed offset.
+    pub fn new() -> Lexer {
+        Lexer {
+            date_formats: DATE_FORMATS.iter().map(|f| f.to_string()).collect(),
+            default_tz: None,
+        }
+    }
+
+    /// Append a chrono format string to try when lexing dates.
+    pub fn with_date_format<S: Into<String>>(mut self, format: S) -> Lexer {
+        self.date_formats.push(format.into());
+        self
+    }
+
+    /// Interpret naive (offset-less) datetimes in the given fixed offset rather
+    /// than the local timezone. The resulting instant is still converted to
+    /// `Local` for storage in [`Token::Date`]. The offset applies only to the
+    /// configured date formats; relative keywords, weekday names and bare
+    /// `%F` dates still resolve in the local timezone.
+    pub fn with_timezone(mut self, offset: FixedOffset) -> Lexer {
+        self.default_tz = Some(offset);
+        self
+    }
+
+    /// Convert a single lexeme into a [`Token`] using this lexer's date policy.
+    pub fn tokenize(&self, s: &str) -> Token {
         if let Ok(num) = s.parse::<f64>() {
             return Token::Float(num);
         }
 
-        for format in DATE_FORMATS.iter() {
-            if let Ok(date) = Local.datetime_from_str(s, format) {
+        if let Some(date) = fuzzy_date(s) {
+            return Token::Date(date);
+        }
+
+        if let Some(date) = offset_date(s) {
+            return Token::Date(date);
+        }
+
+        for format in self.date_formats.iter() {
+            let parsed = match self.default_tz {
+                Some(offset) => offset
+                    .datetime_from_str(s, format)
+                    .map(|dte| dte.with_timezone(&Local)),
+                None => Local.datetime_from_str(s, format),
+            };
+
+            if let Ok(date) = parsed {
                 return Token::Date(date);
             }
         }
@@ -92,12 +315,47 @@ impl<'a> From<&'a str> for Token {
 
             "AND" | "and" => Token::AND,
             "OR" | "or" => Token::OR,
+            "NOT" | "not" | "!" => Token::NOT,
 
             _ => Token::Str(s.to_string()),
         }
     }
 }
 
+/// The default lexer reproduces the historical [`DATE_FORMATS`] and interprets
+/// naive datetimes in the local timezone, so callers using `Token::from(&str)`
+/// still have those three formats accepted. Equivalent to [`Lexer::new`].
+impl Default for Lexer {
+    fn default() -> Lexer {
+        Lexer::new()
+    }
+}
+
+// Attempt to parse a datetime carrying an explicit UTC offset. RFC 3339 and
+// the offset-bearing `%z` formats are tried; on success the instant is
+// converted into the local zone so that `GT`/`LT` comparisons against
+// locally-recorded timestamps stay correct instead of silently reinterpreting
+// the wall-clock time as local.
+fn offset_date(s: &str) -> Option<DateTime<Local>> {
+    if let Ok(dte) = DateTime::parse_from_rfc3339(s) {
+        return Some(dte.with_timezone(&Local));
+    }
+
+    for format in ["%F %H:%M %z", "%FT%T%z"].iter() {
+        if let Ok(dte) = DateTime::parse_from_str(s, format) {
+            return Some(dte.with_timezone(&Local));
+        }
+    }
+
+    None
+}
+
+impl<'a> From<&'a str> for Token {
+    fn from(s: &str) -> Token {
+        Lexer::default().tokenize(s)
+    }
+}
+
 impl From<DateTime<Local>> for Token {
     fn from(dte: DateTime<Local>) -> Token {
         Token::Date(dte)
@@ -122,6 +380,7 @@ impl Into<String> for Token {
 
             Token::AND => "(AND, AND)".to_string(),
             Token::OR => "(OR, OR)".to_string(),
+            Token::NOT => "(NOT, NOT)".to_string(),
 
             Token::LP => "(LP, '(')".to_string(),
             Token::RP => "(RP, ')')".to_string(),
@@ -152,6 +411,9 @@ pub mod tests {
         assert_eq!(Token::from("and"), Token::AND);
         assert_eq!(Token::from("OR"), Token::OR);
         assert_eq!(Token::from("or"), Token::OR);
+        assert_eq!(Token::from("NOT"), Token::NOT);
+        assert_eq!(Token::from("not"), Token::NOT);
+        assert_eq!(Token::from("!"), Token::NOT);
         assert_eq!(Token::from("1.0"), Token::Float(1.0));
         assert_eq!(Token::from("5"), Token::Float(5.0));
         assert_eq!(Token::from("^^"), Token::NLIKE);
@@ -175,6 +437,103 @@ pub mod tests {
         assert_eq!(Token::from("!~"), Token::NLIKE);
     }
 
+    #[test]
+    fn test_fuzzy_dates() {
+        assert_eq!(
+            Token::from("today"),
+            Token::Date(Local::today().and_hms(0, 0, 0))
+        );
+        assert_eq!(
+            Token::from("tomorrow"),
+            Token::Date((Local::today() + Duration::days(1)).and_hms(0, 0, 0))
+        );
+        assert_eq!(
+            Token::from("yesterday"),
+            Token::Date((Local::today() - Duration::days(1)).and_hms(0, 0, 0))
+        );
+
+        // A bare ISO date defaults to midnight.
+        assert_eq!(
+            Token::from("2018-07-04"),
+            Token::Date(
+                Local
+                    .datetime_from_str("2018-07-04 00:00", "%F %H:%M")
+                    .unwrap()
+            )
+        );
+
+        // Weekday resolution always lands on the named weekday.
+        match Token::from("next friday") {
+            Token::Date(d) => assert_eq!(d.weekday(), Weekday::Fri),
+            other => panic!("expected a date, got {:?}", other),
+        }
+
+        // Jan 31 + 1 month clamps to the last day of February.
+        let jan31 = Local.ymd(2018, 1, 31).and_hms(9, 0, 0);
+        assert_eq!(
+            add_months(jan31, 1),
+            Some(Local.ymd(2018, 2, 28).and_hms(9, 0, 0))
+        );
+    }
+
+    #[test]
+    fn test_serde_roundtrip() {
+        let tokens = vec![
+            Token::AND,
+            Token::NOT,
+            Token::Str("hello".to_string()),
+            Token::Float(1.5),
+            Token::Float(std::f64::INFINITY),
+            Token::Date(Local.ymd(2018, 7, 4).and_hms(12, 0, 0)),
+        ];
+
+        for token in tokens {
+            let json = serde_json::to_string(&token).unwrap();
+            assert_eq!(serde_json::from_str::<Token>(&json).unwrap(), token);
+        }
+    }
+
+    #[test]
+    fn test_offset_dates() {
+        // An explicit offset is preserved as an instant, not reinterpreted as
+        // local wall-clock time.
+        let expected = DateTime::parse_from_rfc3339("2018-07-04T12:00:00-03:00")
+            .unwrap()
+            .with_timezone(&Local);
+        assert_eq!(Token::from("2018-07-04T12:00:00-03:00"), Token::Date(expected));
+        assert_eq!(Token::from("2018-07-04 12:00 -0300"), Token::Date(expected));
+    }
+
+    #[test]
+    fn test_lexer_custom_date_format() {
+        let lexer = Lexer::new().with_date_format("%d/%m/%Y %H:%M");
+        assert_eq!(
+            lexer.tokenize("04/07/2018 12:00"),
+            Token::Date(
+                Local
+                    .datetime_from_str("04/07/2018 12:00", "%d/%m/%Y %H:%M")
+                    .unwrap()
+            )
+        );
+
+        // The default lexer still matches the built-in formats.
+        assert_eq!(
+            Lexer::default().tokenize("2018-07-04 12:00"),
+            Token::from("2018-07-04 12:00")
+        );
+
+        // A configured timezone interprets naive datetimes in that offset.
+        let offset = FixedOffset::east(3 * 3600);
+        let expected = offset
+            .datetime_from_str("2018-07-04 12:00", "%F %H:%M")
+            .unwrap()
+            .with_timezone(&Local);
+        assert_eq!(
+            Lexer::new().with_timezone(offset).tokenize("2018-07-04 12:00"),
+            Token::Date(expected)
+        );
+    }
+
     #[test]
     fn test_from_char() {
         assert_eq!(Token::from('('), Token::LP);
@@ -185,5 +544,6 @@ pub mod tests {
         assert_eq!(Token::from('%'), Token::Unexpected("%".to_string()));
         assert_eq!(Token::from('~'), Token::LIKE);
         assert_eq!(Token::from('^'), Token::LIKE);
+        assert_eq!(Token::from('!'), Token::NOT);
     }
 }